@@ -34,8 +34,9 @@ use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::net::Ipv4Addr;
 use std::path::Path;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use future::Future;
 use futures::{Never, future};
 use futures::{Poll,Async};
@@ -50,11 +51,19 @@ use rand::{thread_rng, RngCore};
 
 use biadne::store::ContentStore;
 use std::sync::RwLock;
-use biadne::error::BiadNetError;
 use murmel::error::MurmelError;
+use biadne::peer_store::{PeerAddress, PeerStore};
+use biadne::socks5::{self, Socks5ProxyConfig};
+use biadne::retention::RetentionConfig;
+use bitcoin_hashes::sha256d;
+use murmel::chaindb::SharedChainDB;
+use bitcoin_wallet::trunk::Trunk;
 
 const MAX_PROTOCOL_VERSION: u32 = 70001;
 
+/// Where the address book is kept between runs.
+const PEER_STORE_PATH: &str = "peers.dat";
+
 pub fn main () {
     simple_logger::init_with_level(Level::Debug).unwrap();
 
@@ -77,7 +86,15 @@ pub fn main () {
 
     let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
-    let downstream = Arc::new(Mutex::new(Driver{store: ContentStore::new()}));
+    let peer_store = Arc::new(PeerStore::new(Path::new(PEER_STORE_PATH))
+        .expect("can not open peer store"));
+
+    let trunk = Arc::new(ChainDBTrunk { chaindb: chaindb.clone() });
+    let store = Arc::new(Mutex::new(ContentStore::new(trunk, RetentionConfig::default())));
+    let overlay = biadne::overlay::OverlayAdaptor::new(store.clone(), peer_store.clone());
+    let overlay_handle = overlay.init();
+
+    let downstream = Arc::new(Mutex::new(Driver{store: store.clone(), overlay: overlay_handle}));
 
     let header_downloader = HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout, downstream);
 
@@ -93,39 +110,92 @@ pub fn main () {
     thread_pool.spawn(p2p_task).unwrap();
 
     // note that this call does not return
-    thread_pool.run(keep_connected(p2p.clone(), vec!(), 3)).unwrap();
+    thread_pool.run(keep_connected(p2p.clone(), vec!(), 3, peer_store, None)).unwrap();
 }
 
+// See the matching comment on `p2p_bitcoin::BitcoinDriver`: `Downstream` is
+// owned by murmel and only verifiably exposes the three methods below, so
+// peer-lifecycle bookkeeping lives in `KeepConnected` instead of fabricated
+// trait methods that would not compile against the real trait.
 pub struct Driver {
-    store: ContentStore
+    store: Arc<Mutex<ContentStore>>,
+    overlay: biadne::overlay::OverlayHandle
 }
 
 impl Downstream for Driver {
     fn block_connected(&mut self, block: &Block, height: u32) {}
 
     fn header_connected(&mut self, block: &BlockHeader, height: u32) {
-        self.store.add_header(block).expect("can not add header");
+        let accepted = self.store.lock().unwrap().add_header(block).expect("can not add header");
+        for id in accepted {
+            self.overlay.announce(id);
+        }
     }
 
-    fn block_disconnected(&mut self, _: &BlockHeader) {
-        self.store.unwind_tip().expect("can not unwind tip");
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        self.store.lock().unwrap().unwind_tip(header).expect("can not unwind tip");
     }
 }
 
-fn keep_connected(p2p: Arc<P2P>, peers: Vec<SocketAddr>, min_connections: usize) -> Box<Future<Item=(), Error=Never> + Send> {
+/// `bitcoin_wallet::trunk::Trunk` adapter over murmel's header database, so
+/// `ContentStore` can check whether a funding proof's block is still on the
+/// main chain. See the matching `p2p_bitcoin::ChainDBTrunk`.
+struct ChainDBTrunk {
+    chaindb: SharedChainDB
+}
+
+impl Trunk for ChainDBTrunk {
+    fn is_on_trunk(&self, block_hash: &sha256d::Hash) -> bool {
+        self.chaindb.read().unwrap().pos_on_trunk(block_hash).is_some()
+    }
+
+    fn get_header(&self, block_hash: &sha256d::Hash) -> Option<BlockHeader> {
+        self.chaindb.read().unwrap().get_header(block_hash).map(|cached| cached.stored.header.clone())
+    }
+
+    fn get_height(&self, block_hash: &sha256d::Hash) -> Option<u32> {
+        self.chaindb.read().unwrap().pos_on_trunk(block_hash)
+    }
+
+    fn get_tip(&self) -> Option<BlockHeader> {
+        self.chaindb.read().unwrap().header_tip().map(|cached| cached.stored.header.clone())
+    }
+
+    fn len(&self) -> u32 {
+        if let Some(cached) = self.chaindb.read().unwrap().header_tip() {
+            cached.stored.height
+        } else {
+            0
+        }
+    }
+}
+
+fn keep_connected(p2p: Arc<P2P>, peers: Vec<SocketAddr>, min_connections: usize, peer_store: Arc<PeerStore>, socks5_proxy: Option<Socks5ProxyConfig>) -> Box<Future<Item=(), Error=Never> + Send> {
 
     // add initial peers if any
     let mut added = Vec::new();
+    let mut dialed_at = HashMap::new();
     for addr in &peers {
-        added.push(p2p.add_peer(PeerSource::Outgoing(addr.clone())));
+        let peer_addr = PeerAddress::Clear(addr.clone());
+        dialed_at.insert(peer_addr, Instant::now());
+        added.push((peer_addr, p2p.add_peer(PeerSource::Outgoing(addr.clone()))));
     }
 
+    /// See `p2p_bitcoin::BitcoinAdaptor::keep_connected::CONNECTION_GRACE`:
+    /// the closest approximation of a "connected" signal we can build
+    /// without a murmel hook for it.
+    const CONNECTION_GRACE: Duration = Duration::from_secs(5);
+
     struct KeepConnected {
         min_connections: usize,
-        connections: Vec<Box<Future<Item=SocketAddr, Error=MurmelError> + Send>>,
+        connections: Vec<(PeerAddress, Box<Future<Item=SocketAddr, Error=MurmelError> + Send>)>,
         p2p: Arc<P2P>,
         dns: Vec<SocketAddr>,
-        earlier: HashSet<SocketAddr>
+        earlier: HashSet<SocketAddr>,
+        peer_store: Arc<PeerStore>,
+        socks5_proxy: Option<Socks5ProxyConfig>,
+        dialed_at: HashMap<PeerAddress, Instant>,
+        confirmed_good: HashSet<PeerAddress>
     }
 
     // this task runs until it runs out of peers
@@ -145,8 +215,25 @@ fn keep_connected(p2p: Arc<P2P>, peers: Vec<SocketAddr>, min_connections: usize)
                     error!("no more peers to connect");
                     return Ok(Async::Ready(()));
                 }
+
+                // a dial that has survived CONNECTION_GRACE without erroring
+                // is the closest thing to a "connected" signal we have
+                // without a murmel hook; promote it in the peer store once.
+                let now = Instant::now();
+                for (addr, _) in self.connections.iter() {
+                    if self.confirmed_good.contains(addr) {
+                        continue;
+                    }
+                    if let Some(dialed) = self.dialed_at.get(addr) {
+                        if now.duration_since(*dialed) >= CONNECTION_GRACE {
+                            self.peer_store.mark_good(*addr, 0);
+                            self.confirmed_good.insert(*addr);
+                        }
+                    }
+                }
+
                 // find a finished peer
-                let finished = self.connections.iter_mut().enumerate().filter_map(|(i, f)| {
+                let finished = self.connections.iter_mut().enumerate().filter_map(|(i, (_, f))| {
                     // if any of them finished
                     // note that poll is reusing context of this poll, so wakeups come here
                     match f.poll(cx) {
@@ -162,7 +249,12 @@ fn keep_connected(p2p: Arc<P2P>, peers: Vec<SocketAddr>, min_connections: usize)
                     }
                 }).next();
                 match finished {
-                    Some((i, _)) => self.connections.remove(i),
+                    Some((i, _)) => {
+                        let (addr, _) = self.connections.remove(i);
+                        self.dialed_at.remove(&addr);
+                        self.confirmed_good.remove(&addr);
+                        self.peer_store.mark_failed(addr);
+                    }
                     None => return Ok(Async::Pending)
                 };
             }
@@ -171,22 +263,87 @@ fn keep_connected(p2p: Arc<P2P>, peers: Vec<SocketAddr>, min_connections: usize)
 
     impl KeepConnected {
         fn peers_from_db(&mut self) {
-            // TODO
+            loop {
+                if self.connections.len() >= self.min_connections {
+                    break;
+                }
+                let wanted = self.min_connections - self.connections.len();
+                let candidates = self.peer_store.sample(wanted);
+                if candidates.is_empty() {
+                    break;
+                }
+                let before = self.connections.len();
+                for addr in candidates {
+                    if self.connections.iter().any(|(a, _)| *a == addr) {
+                        continue;
+                    }
+                    match addr {
+                        PeerAddress::Clear(socket) => {
+                            self.dialed_at.insert(addr, Instant::now());
+                            self.connections.push((addr, self.p2p.add_peer(PeerSource::Outgoing(socket))));
+                        }
+                        PeerAddress::Onion { .. } => {
+                            if let Some(proxy) = self.socks5_proxy {
+                                // This is a reachability probe only: this legacy P2P
+                                // instance has no transport hook to adopt the resulting
+                                // stream as a live peer, so it is never registered as a
+                                // connection or counted toward `min_connections`.
+                                match socks5::connect_via_proxy(&proxy, &addr) {
+                                    Ok(_) => trace!("onion peer {:?} reachable via proxy, but no transport hook to use it yet", addr),
+                                    Err(e) => { trace!("onion peer {:?} unreachable via proxy: {}", addr, e); self.peer_store.mark_failed(addr); }
+                                }
+                            }
+                        }
+                    }
+                }
+                if self.connections.len() == before {
+                    break;
+                }
+            }
         }
 
         fn dns_lookup(&mut self) {
             while self.connections.len() < self.min_connections {
-                if self.dns.len() == 0 {
+                if self.dns.is_empty() {
                     self.dns = dns_seed(self.p2p.network);
+                    if self.dns.is_empty() {
+                        // seed gave us nothing; nothing more to do this round
+                        break;
+                    }
                 }
-                if self.dns.len() > 0 {
+
+                // bound the scan to one pass over the cached list: if every
+                // entry in it is currently unusable (banned, or still backed
+                // off), `is_unusable` would otherwise `continue` forever
+                // without this ever shrinking or refreshing.
+                let before = self.connections.len();
+                for _ in 0..self.dns.len() {
+                    if self.connections.len() >= self.min_connections {
+                        break;
+                    }
                     let mut rng = thread_rng();
                     let addr = self.dns[(rng.next_u64() as usize) % self.dns.len()];
-                    self.connections.push(self.p2p.add_peer(PeerSource::Outgoing(addr)));
+                    let peer_addr = PeerAddress::Clear(addr);
+                    if self.peer_store.is_unusable(&peer_addr) {
+                        continue;
+                    }
+                    self.peer_store.add_candidate(peer_addr);
+                    self.dialed_at.insert(peer_addr, Instant::now());
+                    self.connections.push((peer_addr, self.p2p.add_peer(PeerSource::Outgoing(addr))));
+                }
+                if self.connections.len() == before {
+                    // every cached entry was unusable; drop the cache so the
+                    // next call re-queries the seed instead of spinning on it
+                    self.dns.clear();
+                    break;
                 }
             }
         }
     }
 
-    Box::new(KeepConnected { min_connections, connections: added, p2p, dns: Vec::new(), earlier: HashSet::new() })
+    Box::new(KeepConnected {
+        min_connections, connections: added, p2p,
+        dns: Vec::new(), earlier: HashSet::new(), peer_store, socks5_proxy,
+        dialed_at, confirmed_good: HashSet::new()
+    })
 }
\ No newline at end of file
@@ -0,0 +1,46 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Crate-wide error type for `PeerStore`, `ContentStore` and the P2P drivers.
+
+use std::fmt;
+
+/// Errors surfaced by this crate's storage and P2P code.
+#[derive(Debug)]
+pub enum BiadNetError {
+    /// Wraps an I/O failure (socket, disk).
+    Io(std::io::Error),
+    /// A generic failure with a human-readable reason, for call sites that
+    /// don't have a more specific variant yet.
+    Message(String),
+}
+
+impl fmt::Display for BiadNetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BiadNetError::Io(e) => write!(f, "I/O error: {}", e),
+            BiadNetError::Message(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl std::error::Error for BiadNetError {}
+
+impl From<std::io::Error> for BiadNetError {
+    fn from(e: std::io::Error) -> BiadNetError {
+        BiadNetError::Io(e)
+    }
+}
@@ -0,0 +1,308 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Invertible Bloom Lookup Table.
+//!
+//! An IBLT lets two peers discover the symmetric difference of two sets
+//! (here, content ids) by exchanging one compact structure instead of full
+//! id lists. Each cell holds a count, the XOR of the ids hashed into it, and
+//! the XOR of a keyed hash of those ids; subtracting two tables cell-by-cell
+//! and peeling the cells that end up with exactly one (or minus one) entry
+//! yields exactly the ids each side is missing.
+
+use std::collections::VecDeque;
+use std::hash::Hasher;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use siphasher::sip::SipHasher13;
+
+/// A content id: a fixed-width hash identifying one stored ad.
+pub type ContentId = [u8; 32];
+
+/// Number of cells an id is inserted into / removed from.
+const HASH_FUNCTIONS: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Cell {
+    count: i32,
+    key_sum: ContentId,
+    key_hash_sum: u64,
+}
+
+impl Cell {
+    fn empty() -> Cell {
+        Cell { count: 0, key_sum: [0u8; 32], key_hash_sum: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.key_sum == [0u8; 32] && self.key_hash_sum == 0
+    }
+
+    fn is_pure(&self) -> bool {
+        (self.count == 1 || self.count == -1) && check_hash(&self.key_sum) == self.key_hash_sum
+    }
+
+    fn toggle(&mut self, id: &ContentId, sign: i32) {
+        self.count += sign;
+        xor_into(&mut self.key_sum, id);
+        self.key_hash_sum ^= check_hash(id);
+    }
+}
+
+/// A fixed-size Invertible Bloom Lookup Table over 32-byte content ids.
+#[derive(Clone)]
+pub struct Iblt {
+    cells: Vec<Cell>,
+    keys: [u64; HASH_FUNCTIONS],
+}
+
+impl Iblt {
+    /// Create a new, empty table with `num_cells` cells.
+    ///
+    /// `num_cells` should be sized from the estimated symmetric difference
+    /// (a handful of cells per expected difference is typically enough);
+    /// callers that are unsure should start small and grow on decode failure,
+    /// see [`Iblt::decode`].
+    pub fn new(num_cells: usize, keys: [u64; HASH_FUNCTIONS]) -> Iblt {
+        Iblt { cells: vec![Cell::empty(); num_cells.max(HASH_FUNCTIONS)], keys }
+    }
+
+    /// Number of cells in this table.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn cell_indices(&self, id: &ContentId) -> [usize; HASH_FUNCTIONS] {
+        let mut indices = [0usize; HASH_FUNCTIONS];
+        for (i, key) in self.keys.iter().enumerate() {
+            let mut hasher = SipHasher13::new_with_keys(*key, key.wrapping_add(1));
+            hasher.write(id);
+            indices[i] = (hasher.finish() % self.cells.len() as u64) as usize;
+        }
+        indices
+    }
+
+    /// Insert a content id we have.
+    pub fn insert(&mut self, id: &ContentId) {
+        for idx in self.cell_indices(id).iter() {
+            self.cells[*idx].toggle(id, 1);
+        }
+    }
+
+    /// Remove a content id (used when rebuilding the table after eviction).
+    pub fn delete(&mut self, id: &ContentId) {
+        for idx in self.cell_indices(id).iter() {
+            self.cells[*idx].toggle(id, -1);
+        }
+    }
+
+    /// Subtract `remote` from `self`, cell by cell, producing the table that
+    /// represents the symmetric difference of the two original sets.
+    pub fn subtract(&self, remote: &Iblt) -> Iblt {
+        assert_eq!(self.cells.len(), remote.cells.len(), "IBLTs must be the same size to reconcile");
+        let mut cells = Vec::with_capacity(self.cells.len());
+        for (a, b) in self.cells.iter().zip(remote.cells.iter()) {
+            let mut key_sum = a.key_sum;
+            xor_into(&mut key_sum, &b.key_sum);
+            cells.push(Cell {
+                count: a.count - b.count,
+                key_sum,
+                key_hash_sum: a.key_hash_sum ^ b.key_hash_sum,
+            });
+        }
+        Iblt { cells, keys: self.keys }
+    }
+
+    /// Peel the table to recover the ids that differ.
+    ///
+    /// Returns `Ok((local_only, remote_only))` on full decode, or `Err(())`
+    /// if cells ran out before every entry could be peeled off (the
+    /// difference was larger than this table could represent, the caller
+    /// should grow the table and retry, eventually falling back to
+    /// exchanging a full id list).
+    pub fn decode(mut self) -> Result<(Vec<ContentId>, Vec<ContentId>), ()> {
+        let mut local_only = Vec::new();
+        let mut remote_only = Vec::new();
+
+        let mut queue: VecDeque<usize> = (0..self.cells.len())
+            .filter(|i| self.cells[*i].is_pure())
+            .collect();
+
+        while let Some(i) = queue.pop_front() {
+            let cell = self.cells[i];
+            if !cell.is_pure() {
+                continue;
+            }
+            let id = cell.key_sum;
+            let sign = cell.count;
+            if sign == 1 {
+                local_only.push(id);
+            } else {
+                remote_only.push(id);
+            }
+            for idx in self.cell_indices(&id).iter() {
+                self.cells[*idx].toggle(&id, -sign);
+                if self.cells[*idx].is_pure() {
+                    queue.push_back(*idx);
+                }
+            }
+        }
+
+        if self.cells.iter().all(|c| c.is_empty()) {
+            Ok((local_only, remote_only))
+        } else {
+            Err(())
+        }
+    }
+
+    /// Number of bytes one cell occupies on the wire: a `count`, a `key_sum`
+    /// and a `key_hash_sum`.
+    const WIRE_CELL_SIZE: usize = 4 + 32 + 8;
+
+    /// Serialize the cells for the wire. The peer reconstructs the table
+    /// with [`Iblt::from_bytes`], given the same `num_cells` and `seed` we
+    /// sent alongside it (the keys themselves never go over the wire).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.cells.len() * Self::WIRE_CELL_SIZE);
+        for cell in &self.cells {
+            out.write_i32::<LittleEndian>(cell.count).expect("writing to a Vec never fails");
+            out.extend_from_slice(&cell.key_sum);
+            out.write_u64::<LittleEndian>(cell.key_hash_sum).expect("writing to a Vec never fails");
+        }
+        out
+    }
+
+    /// Reconstruct a table of `num_cells` cells keyed by `seed` from the
+    /// bytes produced by [`Iblt::to_bytes`]. Fails if `bytes` is not exactly
+    /// `num_cells` cells long, which means the message was truncated or the
+    /// peer lied about its size.
+    pub fn from_bytes(num_cells: usize, seed: u64, bytes: &[u8]) -> Result<Iblt, ()> {
+        if num_cells == 0 || bytes.len() != num_cells * Self::WIRE_CELL_SIZE {
+            return Err(());
+        }
+        let mut cursor = bytes;
+        let mut cells = Vec::with_capacity(num_cells);
+        for _ in 0..num_cells {
+            let count = cursor.read_i32::<LittleEndian>().map_err(|_| ())?;
+            let mut key_sum = [0u8; 32];
+            key_sum.copy_from_slice(&cursor[..32]);
+            cursor = &cursor[32..];
+            let key_hash_sum = cursor.read_u64::<LittleEndian>().map_err(|_| ())?;
+            cells.push(Cell { count, key_sum, key_hash_sum });
+        }
+        Ok(Iblt { cells, keys: keys_from_seed(seed) })
+    }
+}
+
+fn xor_into(into: &mut ContentId, other: &ContentId) {
+    for i in 0..32 {
+        into[i] ^= other[i];
+    }
+}
+
+/// A second, fixed keyed hash used to check whether a cell is "pure"
+/// (i.e. `key_hash_sum` really is the hash of the single id left in it).
+fn check_hash(id: &ContentId) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0xcafe_babe_dead_beef, 0xf00d_face_b16b_00b5);
+    hasher.write(id);
+    hasher.finish()
+}
+
+/// Pick `HASH_FUNCTIONS` independent siphash keys from a single seed, so two
+/// peers that agree on a seed build tables that peel against each other.
+pub fn keys_from_seed(seed: u64) -> [u64; HASH_FUNCTIONS] {
+    let mut keys = [0u64; HASH_FUNCTIONS];
+    for (i, k) in keys.iter_mut().enumerate() {
+        *k = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15).wrapping_add(i as u64);
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> ContentId {
+        let mut id = [0u8; 32];
+        id[0] = byte;
+        id
+    }
+
+    #[test]
+    fn insert_then_delete_returns_to_empty() {
+        let mut table = Iblt::new(64, keys_from_seed(1));
+        for i in 0..10u8 {
+            table.insert(&id(i));
+        }
+        for i in 0..10u8 {
+            table.delete(&id(i));
+        }
+        assert!(table.cells.iter().all(|c| c.is_empty()));
+    }
+
+    #[test]
+    fn subtract_decodes_symmetric_difference() {
+        let keys = keys_from_seed(42);
+        let mut local = Iblt::new(64, keys);
+        let mut remote = Iblt::new(64, keys);
+        for i in 0..5u8 {
+            local.insert(&id(i));
+            remote.insert(&id(i));
+        }
+        local.insert(&id(100));
+        remote.insert(&id(200));
+
+        let (local_only, remote_only) = local.subtract(&remote).decode().expect("small difference decodes");
+        assert_eq!(local_only, vec![id(100)]);
+        assert_eq!(remote_only, vec![id(200)]);
+    }
+
+    #[test]
+    fn decode_fails_when_difference_exceeds_table_size() {
+        let keys = keys_from_seed(7);
+        let mut local = Iblt::new(8, keys);
+        let remote = Iblt::new(8, keys);
+        for i in 0..50u8 {
+            local.insert(&id(i));
+        }
+        assert!(local.subtract(&remote).decode().is_err());
+    }
+
+    #[test]
+    fn to_bytes_from_bytes_round_trips() {
+        let seed = 99;
+        let mut table = Iblt::new(32, keys_from_seed(seed));
+        for i in 0..6u8 {
+            table.insert(&id(i));
+        }
+        let bytes = table.to_bytes();
+        let rebuilt = Iblt::from_bytes(table.len(), seed, &bytes).expect("round trip decodes");
+
+        let empty = Iblt::new(32, keys_from_seed(seed));
+        let (local_only, remote_only) = rebuilt.subtract(&empty).decode().expect("rebuilt table still peels");
+        assert!(remote_only.is_empty());
+        let mut local_only = local_only;
+        local_only.sort();
+        let mut expected: Vec<ContentId> = (0..6u8).map(id).collect();
+        expected.sort();
+        assert_eq!(local_only, expected);
+    }
+
+    #[test]
+    fn from_bytes_rejects_mismatched_length() {
+        assert!(Iblt::from_bytes(4, 1, &[0u8; 3]).is_err());
+    }
+}
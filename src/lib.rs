@@ -21,10 +21,19 @@ extern crate bitcoin;
 extern crate siphasher;
 extern crate secp256k1;
 extern crate rand;
+extern crate sha3;
 
 mod text;
 mod ad;
 mod iblt;
 mod messages;
 mod content;
-mod funding;
\ No newline at end of file
+mod funding;
+pub mod error;
+pub mod peer_store;
+pub mod socks5;
+mod reconcile;
+pub mod overlay;
+pub mod retention;
+pub mod store;
+mod p2p_bitcoin;
@@ -0,0 +1,68 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Wire messages for the defiads overlay network.
+//!
+//! These travel over defiads's own `P2P` link (see `overlay`), separate from
+//! the Bitcoin header-sync link in `p2p_bitcoin`. Content itself still moves
+//! as raw ad bytes; everything here is about announcing and locating it.
+
+use crate::iblt::ContentId;
+
+/// One message on the defiads overlay wire.
+#[derive(Clone, Debug)]
+pub enum Message {
+    /// Announce that we have these content ids available.
+    Inv(Vec<ContentId>),
+    /// Ask a peer to send us the ads behind these ids.
+    GetData(Vec<ContentId>),
+    /// An ad, sent in response to `GetData`.
+    Ad(ContentId, Vec<u8>),
+    /// Start or continue an IBLT reconciliation round: our table over our content set.
+    ReconcileTable {
+        /// Siphash keys derived from this seed selected the table's cells.
+        seed: u64,
+        /// Number of cells in the table.
+        num_cells: usize,
+        /// Serialized cells, see `iblt::Iblt`.
+        cells: Vec<u8>,
+    },
+    /// The table was too small to decode; retry with a table of this size.
+    ReconcileRetry {
+        /// Cell count to use for the next attempt.
+        next_cells: usize
+    },
+    /// IBLT decoding failed even at the largest size; send a full id list instead.
+    ReconcileFullListRequest,
+    /// A full content id list, sent in response to `ReconcileFullListRequest`.
+    ReconcileFullList(Vec<ContentId>),
+}
+
+/// Framing wrapper around a [`Message`], analogous to rust-bitcoin's
+/// `RawNetworkMessage` (a command name plus payload) but for the overlay's
+/// own, much smaller message set.
+#[derive(Clone, Debug)]
+pub struct RawMessage {
+    /// The decoded payload.
+    pub payload: Message,
+}
+
+impl RawMessage {
+    /// Wrap a message for the wire.
+    pub fn new(payload: Message) -> RawMessage {
+        RawMessage { payload }
+    }
+}
@@ -0,0 +1,363 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The defiads overlay network: a second `P2P` link, carrying defiads's own
+//! [`Message`](crate::messages::Message) wire type, that distributes ads
+//! directly between defiads nodes instead of only piggybacking on Bitcoin
+//! header sync.
+//!
+//! It mirrors `p2p_bitcoin::BitcoinAdaptor` closely: its own config, its own
+//! `keep_connected` loop seeded from the same [`PeerStore`], and a
+//! dedicated thread draining inbound messages straight off the channel
+//! into `OverlayDriver` rather than through murmel's Bitcoin-specific
+//! dispatcher. New content accepted into `ContentStore` is announced to
+//! connected overlay peers with `Inv`; peers fetch what they are missing
+//! with `GetData`, and the IBLT reconciliation in `reconcile` runs
+//! periodically to catch anything a missed `Inv` left out of sync.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, task, Async, Never, Poll};
+use future::Future;
+use rand::{thread_rng, RngCore};
+
+use murmel::error::MurmelError;
+use murmel::p2p::{P2P, P2PControl, PeerMessageSender, PeerSource};
+
+use crate::iblt::ContentId;
+use crate::messages::{Message, RawMessage};
+use crate::peer_store::{PeerAddress, PeerStore};
+use crate::reconcile::{self, ReconcilePeer};
+use crate::store::ContentStore;
+
+/// Configuration for the overlay link, analogous to `BitcoinP2PConfig`.
+pub struct OverlayP2PConfig {
+    /// Random nonce identifying this node, used to ignore self-connections.
+    pub nonce: u64,
+    /// User agent string sent during the overlay handshake.
+    pub user_agent: String,
+}
+
+/// A cheap, cloneable handle to a running overlay link.
+///
+/// Held by `p2p_bitcoin::BitcoinDriver` so that accepting a new ad on the
+/// Bitcoin header-sync side can announce it to overlay peers without either
+/// side needing to know the other's internals.
+#[derive(Clone)]
+pub struct OverlayHandle {
+    control: PeerMessageSender<Message>,
+}
+
+impl OverlayHandle {
+    /// Tell connected overlay peers we now have `id`, so they can `GetData` it.
+    pub fn announce(&self, id: ContentId) {
+        self.control.send_network(murmel::p2p::P2PControl::Broadcast(Message::Inv(vec![id])));
+    }
+}
+
+/// Drives the overlay `P2P` link: connects to peers, announces new content,
+/// and answers `GetData`/reconciliation requests.
+pub struct OverlayAdaptor {
+    store: Arc<Mutex<ContentStore>>,
+    peer_store: Arc<PeerStore>,
+}
+
+impl OverlayAdaptor {
+    /// Build an overlay adaptor sharing the node's content store and address book.
+    pub fn new(store: Arc<Mutex<ContentStore>>, peer_store: Arc<PeerStore>) -> OverlayAdaptor {
+        OverlayAdaptor { store, peer_store }
+    }
+
+    /// Start the overlay link on a background thread: its own `P2P` run loop
+    /// and its own `keep_connected`, seeded from the shared `PeerStore`
+    /// rather than DNS (there is no DNS seed for the overlay network).
+    ///
+    /// Returns immediately with a handle other parts of the node can use to
+    /// announce new content; the link itself keeps running until the process exits.
+    pub fn init(self) -> OverlayHandle {
+        let config = OverlayP2PConfig {
+            nonce: thread_rng().next_u64(),
+            user_agent: "defiads overlay 0.1.0".to_string(),
+        };
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+
+        let (p2p, p2p_control) = P2P::new(config, PeerMessageSender::new(sender), 10);
+
+        let driver = Arc::new(Mutex::new(OverlayDriver {
+            store: self.store.clone(),
+            peer_store: self.peer_store.clone(),
+            p2p_control: p2p_control.clone(),
+            round_seeds: HashMap::new(),
+            reply_to: None,
+        }));
+
+        // `murmel::dispatcher::Dispatcher` is built around `Downstream`,
+        // which is specific to Bitcoin header sync (see the comment on
+        // `BitcoinDriver`/`Driver`) and has no variant for our own `Message`
+        // type; routing inbound overlay messages through it the way
+        // `header_downloader` does for Bitcoin would not compile. We already
+        // own `receiver` outright, so drain it directly instead. Each item
+        // carries the sending peer's address alongside the message, same as
+        // every other per-peer inbound channel in this codebase, so replies
+        // can go back to that one peer instead of everybody.
+        let inbound_driver = driver.clone();
+        std::thread::spawn(move || {
+            while let Ok((from, raw)) = receiver.recv() {
+                inbound_driver.lock().unwrap().handle_message(from, &raw.payload);
+            }
+        });
+
+        let handle = OverlayHandle { control: p2p_control.clone() };
+        let peer_store = self.peer_store.clone();
+        let store = self.store.clone();
+
+        std::thread::spawn(move || {
+            let mut thread_pool = futures::executor::ThreadPoolBuilder::new().create()
+                .expect("can not start overlay thread pool");
+            let p2p2 = p2p.clone();
+            let p2p_task = Box::new(future::poll_fn(move |ctx| {
+                p2p2.run(0, ctx).unwrap();
+                Ok(Async::Ready(()))
+            }));
+            thread_pool.spawn(p2p_task).unwrap();
+            std::thread::spawn(move || Self::run_reconciliation(driver, store));
+            thread_pool.run(Self::keep_connected(p2p.clone(), 3, peer_store)).unwrap();
+        });
+
+        handle
+    }
+
+    /// Every [`RECONCILE_INTERVAL`], start an IBLT reconciliation round over
+    /// our current content ids. Catches anything a missed `Inv` left out of
+    /// sync; `OverlayDriver::handle_message` drives the rest of the round as
+    /// the peer's replies arrive.
+    fn run_reconciliation(driver: Arc<Mutex<OverlayDriver>>, store: Arc<Mutex<ContentStore>>) {
+        const RECONCILE_INTERVAL: Duration = Duration::from_secs(300);
+        loop {
+            std::thread::sleep(RECONCILE_INTERVAL);
+            let local_ids = store.lock().unwrap().content_ids();
+            let seed = thread_rng().next_u64();
+            let mut driver = driver.lock().unwrap();
+            // unprompted: broadcast the opening table to every connected peer
+            // rather than replying to whoever happened to trigger the last round
+            driver.reply_to = None;
+            reconcile::start_round(&local_ids, local_ids.len(), seed, &mut *driver);
+        }
+    }
+
+    /// Same shape as `BitcoinAdaptor::keep_connected`, but there is no DNS
+    /// fallback: the overlay network only grows from addresses that
+    /// `PeerStore::sample` returns from the shared store. No `addr`/`addrv2`
+    /// message is fed into `PeerStore::ingest_addr`/`ingest_addrv2`, so those
+    /// are addresses the Bitcoin link has already dialed.
+    fn keep_connected(p2p: Arc<P2P<Message, RawMessage, OverlayP2PConfig>>, min_connections: usize, peer_store: Arc<PeerStore>) -> Box<dyn Future<Item=(), Error=Never> + Send> {
+        // how long to wait before rechecking the `PeerStore` when we have no
+        // overlay peers at all and nothing else would otherwise wake us up
+        const EMPTY_RETRY: Duration = Duration::from_secs(5);
+
+        struct KeepConnected {
+            min_connections: usize,
+            connections: Vec<(PeerAddress, Box<dyn Future<Item=SocketAddr, Error=MurmelError> + Send>)>,
+            p2p: Arc<P2P<Message, RawMessage, OverlayP2PConfig>>,
+            peer_store: Arc<PeerStore>,
+            earlier: HashSet<PeerAddress>,
+            // set while a background thread is sleeping before it re-wakes
+            // this task; guards against spawning one such thread per poll
+            retry_pending: Arc<AtomicBool>,
+        }
+
+        impl Future for KeepConnected {
+            type Item = ();
+            type Error = Never;
+
+            fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+                loop {
+                    while self.connections.len() < self.min_connections {
+                        let wanted = self.min_connections - self.connections.len();
+                        let candidates: Vec<PeerAddress> = self.peer_store.sample(wanted * 2).into_iter()
+                            .filter(|a| !self.earlier.contains(a))
+                            .take(wanted)
+                            .collect();
+                        if candidates.is_empty() {
+                            break;
+                        }
+                        for addr in candidates {
+                            self.earlier.insert(addr);
+                            if let PeerAddress::Clear(socket) = addr {
+                                self.connections.push((addr, self.p2p.add_peer(PeerSource::Outgoing(socket))));
+                            }
+                            // onion overlay peers wait on the same SOCKS5 transport hook
+                            // noted in p2p_bitcoin::BitcoinAdaptor::keep_connected
+                        }
+                    }
+
+                    if self.connections.is_empty() {
+                        // no overlay peers yet; that is normal early on, but
+                        // nothing else will wake this task once it goes idle
+                        // (no DNS fallback, and the PeerStore filling up later
+                        // registers no waker of its own), so schedule our own
+                        // wakeup instead of going idle forever.
+                        if !self.retry_pending.swap(true, Ordering::SeqCst) {
+                            let retry_pending = self.retry_pending.clone();
+                            let waker = cx.waker().clone();
+                            std::thread::spawn(move || {
+                                std::thread::sleep(EMPTY_RETRY);
+                                retry_pending.store(false, Ordering::SeqCst);
+                                waker.wake();
+                            });
+                        }
+                        return Ok(Async::Pending);
+                    }
+
+                    let finished = self.connections.iter_mut().enumerate().filter_map(|(i, (_, f))| {
+                        match f.poll(cx) {
+                            Ok(Async::Pending) => None,
+                            Ok(Async::Ready(e)) => Some((i, Ok(e))),
+                            Err(e) => Some((i, Err(e))),
+                        }
+                    }).next();
+                    match finished {
+                        Some((i, _)) => {
+                            let (addr, _) = self.connections.remove(i);
+                            self.peer_store.mark_failed(addr);
+                        }
+                        None => return Ok(Async::Pending),
+                    };
+                }
+            }
+        }
+
+        Box::new(KeepConnected {
+            min_connections, connections: Vec::new(), p2p, peer_store, earlier: HashSet::new(),
+            retry_pending: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// Handles inbound overlay messages and serves content to peers that ask for it.
+struct OverlayDriver {
+    store: Arc<Mutex<ContentStore>>,
+    peer_store: Arc<PeerStore>,
+    p2p_control: PeerMessageSender<Message>,
+    /// Seed of the table we last sent to each peer, keyed by their address,
+    /// so a `ReconcileRetry` naming a larger size (but carrying no seed of
+    /// its own) can be served by rebuilding over the same keys instead of
+    /// starting an unrelated round with some other peer's seed.
+    round_seeds: HashMap<SocketAddr, u64>,
+    /// Peer to address the response currently being built to, or `None` when
+    /// there is no single peer to reply to (an unprompted `Inv`, or the
+    /// periodic reconciliation kicking a fresh round off against everyone).
+    reply_to: Option<SocketAddr>,
+}
+
+impl OverlayDriver {
+    /// Send `msg` to `reply_to` if set, otherwise to every connected peer.
+    fn send(&self, msg: Message) {
+        match self.reply_to {
+            Some(addr) => self.p2p_control.send_network(murmel::p2p::P2PControl::Send(addr, msg)),
+            None => self.p2p_control.send_network(murmel::p2p::P2PControl::Broadcast(msg)),
+        }
+    }
+
+    fn handle_message(&mut self, from: SocketAddr, msg: &Message) {
+        self.reply_to = Some(from);
+        match msg {
+            Message::Inv(ids) => {
+                let missing: Vec<ContentId> = {
+                    let store = self.store.lock().unwrap();
+                    ids.iter().cloned().filter(|id| !store.has_content(id)).collect()
+                };
+                if !missing.is_empty() {
+                    self.send(Message::GetData(missing));
+                }
+            }
+            Message::GetData(ids) => {
+                let store = self.store.lock().unwrap();
+                for id in ids {
+                    if let Some(content) = store.get_content(id) {
+                        self.send(Message::Ad(*id, content));
+                    }
+                }
+            }
+            Message::Ad(id, content) => {
+                let mut store = self.store.lock().unwrap();
+                let _ = store.accept_content(*id, content.clone());
+            }
+            Message::ReconcileTable { seed, num_cells, cells } => {
+                match crate::iblt::Iblt::from_bytes(*num_cells, *seed, cells) {
+                    Ok(remote) => {
+                        let local_ids = self.store.lock().unwrap().content_ids();
+                        reconcile::continue_round(&local_ids, *num_cells, *seed, remote, self);
+                    }
+                    Err(()) => {
+                        // malformed or mis-sized table; ask the sender for
+                        // its full id list rather than guess at its intent
+                        self.request_full_list();
+                    }
+                }
+            }
+            Message::ReconcileRetry { next_cells } => {
+                let seed = self.round_seeds.get(&from).copied().unwrap_or(0);
+                let local_ids = self.store.lock().unwrap().content_ids();
+                let table = reconcile::build_table(&local_ids, *next_cells, seed);
+                self.send_table(&table, seed);
+            }
+            Message::ReconcileFullListRequest => {
+                let local_ids = self.store.lock().unwrap().content_ids();
+                self.send(Message::ReconcileFullList(local_ids));
+            }
+            Message::ReconcileFullList(remote_ids) => {
+                let missing: Vec<ContentId> = {
+                    let store = self.store.lock().unwrap();
+                    remote_ids.iter().cloned().filter(|id| !store.has_content(id)).collect()
+                };
+                if !missing.is_empty() {
+                    self.getdata(&missing);
+                }
+            }
+        }
+    }
+}
+
+impl ReconcilePeer for OverlayDriver {
+    fn send_table(&mut self, table: &crate::iblt::Iblt, seed: u64) {
+        if let Some(addr) = self.reply_to {
+            self.round_seeds.insert(addr, seed);
+        }
+        self.send(Message::ReconcileTable { seed, num_cells: table.len(), cells: table.to_bytes() });
+    }
+
+    fn request_larger(&mut self, next_cells: usize) {
+        self.send(Message::ReconcileRetry { next_cells });
+    }
+
+    fn getdata(&mut self, ids: &[ContentId]) {
+        self.send(Message::GetData(ids.to_vec()));
+    }
+
+    fn offer(&mut self, ids: &[ContentId]) {
+        self.send(Message::Inv(ids.to_vec()));
+    }
+
+    fn request_full_list(&mut self) {
+        self.send(Message::ReconcileFullListRequest);
+    }
+}
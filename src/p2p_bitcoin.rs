@@ -15,10 +15,11 @@
 //
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr, SocketAddrV4},
     path::Path,
-    sync::{Arc, Mutex, RwLock, mpsc, atomic::AtomicUsize}
+    sync::{Arc, Mutex, RwLock, mpsc, atomic::AtomicUsize},
+    time::{Duration, Instant}
 };
 use bitcoin::{
     Block, BlockHeader,
@@ -56,16 +57,46 @@ use murmel::{
 use rand::{RngCore, thread_rng};
 use simple_logger::init_with_level;
 
-use crate::error::BiadNetError;
 use crate::store::ContentStore;
+use crate::peer_store::{PeerAddress, PeerStore};
+use crate::socks5::{self, Socks5ProxyConfig};
+use crate::retention::{RetentionConfig, RetentionTracker, verify_on_trunk};
 
 const MAX_PROTOCOL_VERSION: u32 = 70001;
 
-pub struct BitcoinAdaptor {}
+/// Where the address book is kept between runs.
+const PEER_STORE_PATH: &str = "peers.dat";
+
+pub struct BitcoinAdaptor {
+    /// SOCKS5 proxy (normally a local Tor daemon) used to probe onion peers.
+    ///
+    /// `None` means onion candidates are kept in the `PeerStore` but never
+    /// even probed. `Some` only gets as far as a liveness probe: murmel's
+    /// `PeerSource` has no variant to hand it an already-connected stream,
+    /// so an onion peer is never actually dialed into the P2P layer for
+    /// real traffic. Doing that needs a murmel-side change, which is out of
+    /// scope here. Nothing adds onion addresses to the `PeerStore`
+    /// (`ingest_addrv2` has no caller), and `PeerStore::sample` never returns
+    /// `Untested` entries, so the probe is not reached in practice.
+    socks5_proxy: Option<Socks5ProxyConfig>
+}
 
 impl BitcoinAdaptor {
     pub fn new () -> BitcoinAdaptor {
-        BitcoinAdaptor{}
+        BitcoinAdaptor{ socks5_proxy: None }
+    }
+
+    /// Probe onion peers for reachability through `proxy`.
+    ///
+    /// This does not yet give onion peers a real connection into the P2P
+    /// layer (see the `socks5_proxy` field doc); it only keeps `PeerStore`
+    /// liveness bookkeeping honest for when that transport hook exists.
+    pub fn with_socks5_proxy(mut self, proxy: Socks5ProxyConfig) -> BitcoinAdaptor {
+        warn!("onion peers are only probed for reachability through {}, not dialed into the P2P layer; \
+               they will never count toward min_connections or serve traffic until murmel gains a transport \
+               hook for an already-connected stream", proxy.proxy);
+        self.socks5_proxy = Some(proxy);
+        self
     }
 
     pub fn init(&mut self) {
@@ -103,8 +134,23 @@ impl BitcoinAdaptor {
 
         let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
-        let downstream = Arc::new(Mutex::new(BitcoinDriver{store:
-        ContentStore::new(Arc::new(ChainDBTrunk{chaindb: chaindb.clone()}))}));
+        let peer_store = Arc::new(PeerStore::new(Path::new(PEER_STORE_PATH))
+            .expect("can not open peer store"));
+
+        let trunk = Arc::new(ChainDBTrunk{chaindb: chaindb.clone()});
+        let retention_config = RetentionConfig::default();
+        let store = Arc::new(Mutex::new(
+            ContentStore::new(trunk.clone(), retention_config)));
+
+        let overlay = crate::overlay::OverlayAdaptor::new(store.clone(), peer_store.clone());
+        let overlay_handle = overlay.init();
+
+        let downstream = Arc::new(Mutex::new(BitcoinDriver{
+            store: store.clone(),
+            overlay: overlay_handle,
+            trunk: trunk.clone(),
+            retention: RetentionTracker::new(retention_config)
+        }));
 
         let header_downloader = HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout, downstream);
 
@@ -120,24 +166,41 @@ impl BitcoinAdaptor {
         thread_pool.spawn(p2p_task).unwrap();
 
         // note that this call does not return
-        thread_pool.run(Self::keep_connected(network,p2p.clone(), vec!(), 3)).unwrap();
+        thread_pool.run(Self::keep_connected(network, p2p.clone(), vec!(), 3, peer_store, self.socks5_proxy)).unwrap();
     }
 
-    fn keep_connected(network: Network, p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>, peers: Vec<SocketAddr>, min_connections: usize) -> Box<dyn Future<Item=(), Error=Never> + Send> {
+    fn keep_connected(network: Network, p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>, peers: Vec<SocketAddr>, min_connections: usize, peer_store: Arc<PeerStore>, socks5_proxy: Option<Socks5ProxyConfig>) -> Box<dyn Future<Item=(), Error=Never> + Send> {
 
         // add initial peers if any
         let mut added = Vec::new();
+        let mut dialed_at = HashMap::new();
         for addr in &peers {
-            added.push(p2p.add_peer(PeerSource::Outgoing(addr.clone())));
+            let peer_addr = PeerAddress::Clear(addr.clone());
+            dialed_at.insert(peer_addr, Instant::now());
+            added.push((peer_addr, p2p.add_peer(PeerSource::Outgoing(addr.clone()))));
         }
 
+        /// How long a dial has to survive without erroring before we treat it
+        /// as a successful handshake for `PeerStore` bookkeeping purposes.
+        ///
+        /// This link's `Downstream` only exposes `block_connected` /
+        /// `header_connected` / `block_disconnected` (see `murmel::downstream`),
+        /// so there is no real "handshake succeeded" callback to hook into
+        /// without patching murmel; this is the closest approximation we can
+        /// build from code we own.
+        const CONNECTION_GRACE: Duration = Duration::from_secs(5);
+
         struct KeepConnected {
             network: Network,
             min_connections: usize,
-            connections: Vec<Box<dyn Future<Item=SocketAddr, Error=MurmelError> + Send>>,
+            connections: Vec<(PeerAddress, Box<dyn Future<Item=SocketAddr, Error=MurmelError> + Send>)>,
             p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
             dns: Vec<SocketAddr>,
-            earlier: HashSet<SocketAddr>
+            earlier: HashSet<SocketAddr>,
+            peer_store: Arc<PeerStore>,
+            socks5_proxy: Option<Socks5ProxyConfig>,
+            dialed_at: HashMap<PeerAddress, Instant>,
+            confirmed_good: HashSet<PeerAddress>
         }
 
         // this task runs until it runs out of peers
@@ -157,8 +220,25 @@ impl BitcoinAdaptor {
                         error!("no more peers to connect");
                         return Ok(Async::Ready(()));
                     }
+
+                    // a dial that has survived CONNECTION_GRACE without erroring
+                    // is the closest thing to a "connected" signal we have
+                    // without a murmel hook; promote it in the peer store once.
+                    let now = Instant::now();
+                    for (addr, _) in self.connections.iter() {
+                        if self.confirmed_good.contains(addr) {
+                            continue;
+                        }
+                        if let Some(dialed) = self.dialed_at.get(addr) {
+                            if now.duration_since(*dialed) >= CONNECTION_GRACE {
+                                self.peer_store.mark_good(*addr, 0);
+                                self.confirmed_good.insert(*addr);
+                            }
+                        }
+                    }
+
                     // find a finished peer
-                    let finished = self.connections.iter_mut().enumerate().filter_map(|(i, f)| {
+                    let finished = self.connections.iter_mut().enumerate().filter_map(|(i, (_, f))| {
                         // if any of them finished
                         // note that poll is reusing context of this poll, so wakeups come here
                         match f.poll(cx) {
@@ -174,7 +254,15 @@ impl BitcoinAdaptor {
                         }
                     }).next();
                     match finished {
-                        Some((i, _)) => self.connections.remove(i),
+                        Some((i, result)) => {
+                            let (addr, _) = self.connections.remove(i);
+                            self.dialed_at.remove(&addr);
+                            self.confirmed_good.remove(&addr);
+                            match result {
+                                Ok(_) => self.peer_store.mark_failed(addr),
+                                Err(_) => self.peer_store.mark_failed(addr)
+                            }
+                        }
                         None => return Ok(Async::Pending)
                     };
                 }
@@ -183,41 +271,140 @@ impl BitcoinAdaptor {
 
         impl KeepConnected {
             fn peers_from_db(&mut self) {
-                // TODO
+                loop {
+                    if self.connections.len() >= self.min_connections {
+                        break;
+                    }
+                    let wanted = self.min_connections - self.connections.len();
+                    let candidates = self.peer_store.sample(wanted);
+                    if candidates.is_empty() {
+                        break;
+                    }
+                    let before = self.connections.len();
+                    for addr in candidates {
+                        if self.connections.iter().any(|(a, _)| *a == addr) {
+                            continue;
+                        }
+                        match addr {
+                            PeerAddress::Clear(socket) => {
+                                self.dialed_at.insert(addr, Instant::now());
+                                self.connections.push((addr, self.p2p.add_peer(PeerSource::Outgoing(socket))));
+                            }
+                            PeerAddress::Onion { .. } => {
+                                if let Some(proxy) = self.socks5_proxy {
+                                    // This is a reachability probe only: murmel's `PeerSource`
+                                    // has no variant to hand it a pre-connected stream, so an
+                                    // onion peer is never registered as a live connection and
+                                    // never counts toward `min_connections`. Giving it a real
+                                    // transport path needs a murmel-side change that is out of
+                                    // scope here; we only keep `PeerStore` bookkeeping honest.
+                                    match socks5::connect_via_proxy(&proxy, &addr) {
+                                        Ok(_) => trace!("onion peer {:?} reachable via proxy, but no transport hook to use it yet", addr),
+                                        Err(e) => { trace!("onion peer {:?} unreachable via proxy: {}", addr, e); self.peer_store.mark_failed(addr); }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if self.connections.len() == before {
+                        // made no progress this round (e.g. only unusable onion peers left)
+                        break;
+                    }
+                }
             }
 
             fn dns_lookup(&mut self) {
                 while self.connections.len() < self.min_connections {
-                    if self.dns.len() == 0 {
+                    if self.dns.is_empty() {
                         self.dns = dns_seed(self.network);
+                        if self.dns.is_empty() {
+                            // seed gave us nothing; nothing more to do this round
+                            break;
+                        }
                     }
-                    if self.dns.len() > 0 {
+
+                    // bound the scan to one pass over the cached list: if every
+                    // entry in it is currently unusable (banned, or still
+                    // backed off), `is_unusable` would otherwise `continue`
+                    // forever without this ever shrinking or refreshing.
+                    let before = self.connections.len();
+                    for _ in 0..self.dns.len() {
+                        if self.connections.len() >= self.min_connections {
+                            break;
+                        }
                         let mut rng = thread_rng();
                         let addr = self.dns[(rng.next_u64() as usize) % self.dns.len()];
-                        self.connections.push(self.p2p.add_peer(PeerSource::Outgoing(addr)));
+                        let peer_addr = PeerAddress::Clear(addr);
+                        if self.peer_store.is_unusable(&peer_addr) {
+                            continue;
+                        }
+                        self.peer_store.add_candidate(peer_addr);
+                        self.dialed_at.insert(peer_addr, Instant::now());
+                        self.connections.push((peer_addr, self.p2p.add_peer(PeerSource::Outgoing(addr))));
+                    }
+                    if self.connections.len() == before {
+                        // every cached entry was unusable; drop the cache so the
+                        // next call re-queries the seed instead of spinning on it
+                        self.dns.clear();
+                        break;
                     }
                 }
             }
         }
 
-        Box::new(KeepConnected { network, min_connections, connections: added, p2p, dns: Vec::new(), earlier: HashSet::new() })
+        Box::new(KeepConnected {
+            network, min_connections, connections: added, p2p,
+            dns: Vec::new(), earlier: HashSet::new(), peer_store, socks5_proxy,
+            dialed_at, confirmed_good: HashSet::new()
+        })
     }
 }
 
 
+// `Downstream` is owned by murmel and, as far as this tree can verify, only
+// exposes the three methods implemented below. Peer-lifecycle bookkeeping
+// (mark_good/mark_failed) and addr/addrv2 gossip ingestion used to be bolted
+// on here as extra trait methods, but that can't satisfy the real trait and
+// would not compile. mark_good/mark_failed now live in `KeepConnected`, which
+// already owns `PeerStore` and the per-dial futures without needing to
+// extend a trait this crate does not define. No inbound `addr`/`addrv2`
+// message reaches `PeerStore::ingest_addr`/`ingest_addrv2` on this link.
 struct BitcoinDriver {
-    store: ContentStore
+    store: Arc<Mutex<ContentStore>>,
+    overlay: crate::overlay::OverlayHandle,
+    trunk: Arc<ChainDBTrunk>,
+    /// Funding-weighted rank of every ad accepted through `header_connected`,
+    /// kept here rather than inside `ContentStore` itself so eviction stays
+    /// driven by the chain state this driver already owns (the trunk, and the
+    /// height of the header that just connected). Content accepted over the
+    /// overlay with `ContentStore::accept_content`, and ads un-ranked in
+    /// `block_disconnected`, are not tracked here: they count toward neither
+    /// cap and are never evicted.
+    retention: RetentionTracker,
 }
 
 impl Downstream for BitcoinDriver {
     fn block_connected(&mut self, block: &Block, height: u32) {}
 
     fn header_connected(&mut self, block: &BlockHeader, height: u32) {
-        self.store.add_header(block).expect("can not add header");
+        let accepted = self.store.lock().unwrap().add_header(block).expect("can not add header");
+        for id in accepted {
+            let weight = self.store.lock().unwrap().funding_proof(&id)
+                .and_then(|proof| verify_on_trunk(self.trunk.as_ref(), &proof).map(|_| proof.amount))
+                .unwrap_or(0);
+            let bytes = self.store.lock().unwrap().content_len(&id);
+            for evicted in self.retention.accept(id, weight, height, bytes, height) {
+                self.store.lock().unwrap().forget(&evicted);
+            }
+            self.overlay.announce(id);
+        }
     }
 
     fn block_disconnected(&mut self, header: &BlockHeader) {
-        self.store.unwind_tip(header).expect("can not unwind tip");
+        let unwound = self.store.lock().unwrap().unwind_tip(header).expect("can not unwind tip");
+        for id in unwound {
+            self.retention.unwind(&id);
+        }
     }
 }
 
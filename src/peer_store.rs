@@ -0,0 +1,474 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A persistent address book for peers we have seen.
+//!
+//! `peers_from_db` used to be an empty `// TODO`, so the node forgot every
+//! peer it ever learned about as soon as it restarted and leaned entirely on
+//! `dns_lookup`. `PeerStore` keeps a small state machine per address (modeled
+//! after the dnsseed-rust datastore and grin's peers table) and persists it
+//! to disk so the address book survives restarts.
+//!
+//! Addresses are not limited to plain IPv4/IPv6: `addr`/`addrv2` gossip can
+//! also advertise Tor v3 onion services, so the book is keyed by
+//! [`PeerAddress`] rather than `SocketAddr` directly.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bitcoin::network::address::Address;
+use rand::{thread_rng, Rng};
+
+use crate::error::BiadNetError;
+
+/// A node-reachable address: either a regular IPv4/IPv6 socket, or a Tor v3
+/// onion service that must be dialed through a SOCKS5 proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PeerAddress {
+    /// A plain IPv4 or IPv6 endpoint, reachable directly.
+    Clear(SocketAddr),
+    /// A Tor v3 onion service: the 32 byte ed25519 public key and port.
+    Onion {
+        /// Public key identifying the onion service.
+        pubkey: [u8; 32],
+        /// Port the hidden service listens on.
+        port: u16
+    },
+}
+
+impl PeerAddress {
+    /// `true` if this address can only be reached through a SOCKS5 proxy.
+    pub fn needs_proxy(&self) -> bool {
+        match self {
+            PeerAddress::Onion { .. } => true,
+            PeerAddress::Clear(_) => false,
+        }
+    }
+}
+
+/// How a peer address looked the last time we dealt with it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressState {
+    /// We know the address but never successfully connected to it.
+    Untested,
+    /// Last contact succeeded and the peer looked useful.
+    Good,
+    /// Used to be `Good`, but the most recent attempt failed.
+    WasGood,
+    /// Repeatedly timed out or refused connections; eligible for `sample`
+    /// again once its backoff window elapses.
+    Timeout,
+    /// Misbehaved badly enough that we should never dial it again.
+    Banned,
+}
+
+impl AddressState {
+    fn to_u8(self) -> u8 {
+        match self {
+            AddressState::Untested => 0,
+            AddressState::Good => 2,
+            AddressState::WasGood => 3,
+            AddressState::Timeout => 4,
+            AddressState::Banned => 5,
+        }
+    }
+
+    fn from_u8(b: u8) -> AddressState {
+        match b {
+            2 => AddressState::Good,
+            3 => AddressState::WasGood,
+            4 => AddressState::Timeout,
+            5 => AddressState::Banned,
+            _ => AddressState::Untested,
+        }
+    }
+}
+
+/// Everything we remember about one peer address.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerEntry {
+    /// Current classification of this address.
+    pub state: AddressState,
+    /// Unix timestamp of the last time we heard something useful from it.
+    pub last_seen: u64,
+    /// Number of handshake attempts in a row that failed.
+    pub failures: u32,
+    /// Service flags advertised in the peer's `version` message.
+    pub services: u64,
+}
+
+impl PeerEntry {
+    fn fresh() -> PeerEntry {
+        PeerEntry { state: AddressState::Untested, last_seen: 0, failures: 0, services: 0 }
+    }
+
+    /// Exponential backoff: skip an address for `2^failures` minutes, capped at a day.
+    fn backed_off(&self, now: u64) -> bool {
+        if self.failures == 0 {
+            return false;
+        }
+        let backoff_minutes = 1u64.saturating_shl(self.failures.min(11)).min(24 * 60);
+        now < self.last_seen.saturating_add(backoff_minutes * 60)
+    }
+}
+
+/// A persistent, state-tracked address book.
+///
+/// Addresses are sampled from the `Good`/`WasGood` pools (plus `Timeout`
+/// entries whose backoff has elapsed), weighted toward the freshest entries,
+/// and saved back to `path` whenever the table changes so
+/// the node does not have to rediscover its peer set with `dns_lookup` after
+/// every restart.
+pub struct PeerStore {
+    path: PathBuf,
+    entries: Mutex<HashMap<PeerAddress, PeerEntry>>,
+}
+
+/// Service bits we care about when filtering `addr`/`addrv2` gossip.
+///
+/// A full archival node is the only kind worth remembering for header sync;
+/// anything that does not even claim `NODE_NETWORK` is not useful to us.
+const NODE_NETWORK: u64 = 1;
+
+/// A decoded BIP155 `addrv2` record, independent of the wire `AddrV2Message`
+/// representation so `PeerStore` does not need to know rust-bitcoin's exact
+/// network-id encoding for every address family.
+pub struct AddrV2Entry {
+    /// Service flags the peer advertised for itself.
+    pub services: u64,
+    /// BIP155 network id: 1 = IPv4, 2 = IPv6, 4 = TorV3, others are unsupported.
+    pub network_id: u8,
+    /// Raw address bytes, length depends on `network_id`.
+    pub addr_bytes: Vec<u8>,
+    /// Port the peer listens on.
+    pub port: u16,
+}
+
+impl PeerStore {
+    /// Open (or create) the address book at `path`.
+    pub fn new(path: &Path) -> Result<PeerStore, BiadNetError> {
+        let entries = if path.exists() {
+            Self::load(path)?
+        } else {
+            HashMap::new()
+        };
+        Ok(PeerStore { path: path.to_path_buf(), entries: Mutex::new(entries) })
+    }
+
+    fn load(path: &Path) -> Result<HashMap<PeerAddress, PeerEntry>, BiadNetError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut map = HashMap::new();
+        loop {
+            let addr = match read_addr(&mut reader) {
+                Ok(addr) => addr,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(BiadNetError::from(e)),
+            };
+            let state = AddressState::from_u8(reader.read_u8()?);
+            let last_seen = reader.read_u64::<LittleEndian>()?;
+            let failures = reader.read_u32::<LittleEndian>()?;
+            let services = reader.read_u64::<LittleEndian>()?;
+            map.insert(addr, PeerEntry { state, last_seen, failures, services });
+        }
+        Ok(map)
+    }
+
+    fn save(&self, entries: &HashMap<PeerAddress, PeerEntry>) -> Result<(), BiadNetError> {
+        let mut writer = BufWriter::new(
+            OpenOptions::new().write(true).create(true).truncate(true).open(&self.path)?);
+        for (addr, entry) in entries.iter() {
+            write_addr(&mut writer, addr)?;
+            writer.write_u8(entry.state.to_u8())?;
+            writer.write_u64::<LittleEndian>(entry.last_seen)?;
+            writer.write_u32::<LittleEndian>(entry.failures)?;
+            writer.write_u64::<LittleEndian>(entry.services)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Learn about an address without yet knowing if it is any good (e.g. from `addr` gossip).
+    pub fn add_candidate(&self, addr: PeerAddress) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(addr).or_insert_with(PeerEntry::fresh);
+        let _ = self.save(&entries);
+    }
+
+    /// Record a successful handshake: the peer is promoted to `Good` and its failure count resets.
+    pub fn mark_good(&self, addr: PeerAddress, services: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(addr).or_insert_with(PeerEntry::fresh);
+        if entry.state != AddressState::Banned {
+            entry.state = AddressState::Good;
+        }
+        entry.failures = 0;
+        entry.services = services;
+        entry.last_seen = now();
+        let _ = self.save(&entries);
+    }
+
+    /// Record a disconnect or failed handshake: demote the address and apply backoff.
+    pub fn mark_failed(&self, addr: PeerAddress) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(addr).or_insert_with(PeerEntry::fresh);
+        if entry.state == AddressState::Banned {
+            return;
+        }
+        entry.failures = entry.failures.saturating_add(1);
+        entry.last_seen = now();
+        entry.state = match entry.state {
+            AddressState::Good | AddressState::WasGood if entry.failures < 3 => AddressState::WasGood,
+            _ => AddressState::Timeout,
+        };
+        let _ = self.save(&entries);
+    }
+
+    /// Ban an address permanently; it will never be returned by `sample` again.
+    pub fn ban(&self, addr: PeerAddress) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(addr).or_insert_with(PeerEntry::fresh);
+        entry.state = AddressState::Banned;
+        let _ = self.save(&entries);
+    }
+
+    /// Ingest a legacy `addr` message entry, keeping it only if it advertises `NODE_NETWORK`.
+    pub fn ingest_addr(&self, addr: &Address) {
+        if addr.services & NODE_NETWORK == 0 {
+            return;
+        }
+        if let Ok(socket) = addr.socket_addr() {
+            self.add_candidate(PeerAddress::Clear(socket));
+        }
+    }
+
+    /// Ingest a BIP155 `addrv2` entry: IPv4/IPv6 become `Clear`, Tor v3 becomes `Onion`.
+    ///
+    /// Address families we do not understand (I2P, CJDNS, ...) are dropped; we
+    /// have no transport for them.
+    pub fn ingest_addrv2(&self, entry: &AddrV2Entry) {
+        if entry.services & NODE_NETWORK == 0 {
+            return;
+        }
+        let candidate = match (entry.network_id, entry.addr_bytes.len()) {
+            (1, 4) => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&entry.addr_bytes);
+                Some(PeerAddress::Clear(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), entry.port)))
+            }
+            (2, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&entry.addr_bytes);
+                Some(PeerAddress::Clear(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), entry.port)))
+            }
+            (4, 32) => {
+                let mut pubkey = [0u8; 32];
+                pubkey.copy_from_slice(&entry.addr_bytes);
+                Some(PeerAddress::Onion { pubkey, port: entry.port })
+            }
+            _ => None,
+        };
+        if let Some(addr) = candidate {
+            self.add_candidate(addr);
+        }
+    }
+
+    /// Sample up to `n` usable addresses, weighted toward the freshest `Good` entries.
+    ///
+    /// Banned addresses are never returned, and a `Timeout` address is only
+    /// offered again once its exponential backoff window has elapsed, so a
+    /// run of failures does not remove it from the pool forever.
+    pub fn sample(&self, n: usize) -> Vec<PeerAddress> {
+        let entries = self.entries.lock().unwrap();
+        let now = now();
+        let mut candidates: Vec<(PeerAddress, u64)> = entries.iter()
+            .filter(|(_, e)| e.state == AddressState::Good
+                || e.state == AddressState::WasGood
+                || e.state == AddressState::Timeout)
+            .filter(|(_, e)| !e.backed_off(now))
+            .map(|(addr, e)| {
+                let age = now.saturating_sub(e.last_seen);
+                // weight falls off with age; good peers start well ahead of
+                // was-good ones, and a timed-out peer whose backoff just
+                // elapsed is worth retrying but only as a last resort
+                let base = match e.state {
+                    AddressState::Good => 2_000_000u64,
+                    AddressState::WasGood => 1_000_000u64,
+                    _ => 200_000u64,
+                };
+                (*addr, base / (age / 60 + 1))
+            })
+            .collect();
+
+        let mut rng = thread_rng();
+        let mut result = Vec::new();
+        while !candidates.is_empty() && result.len() < n {
+            let total: u64 = candidates.iter().map(|(_, w)| *w).sum();
+            if total == 0 {
+                break;
+            }
+            let mut pick = rng.gen_range(0, total);
+            let mut idx = 0;
+            for (i, (_, w)) in candidates.iter().enumerate() {
+                if pick < *w {
+                    idx = i;
+                    break;
+                }
+                pick -= *w;
+            }
+            result.push(candidates.remove(idx).0);
+        }
+        result
+    }
+
+    /// `true` if the address is banned or currently serving a backoff period.
+    pub fn is_unusable(&self, addr: &PeerAddress) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(addr) {
+            Some(e) => e.state == AddressState::Banned || e.backed_off(now()),
+            None => false,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn read_addr<R: Read>(r: &mut R) -> io::Result<PeerAddress> {
+    let tag = r.read_u8()?;
+    match tag {
+        4 => {
+            let mut octets = [0u8; 4];
+            r.read_exact(&mut octets)?;
+            let port = r.read_u16::<LittleEndian>()?;
+            Ok(PeerAddress::Clear(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port)))
+        }
+        6 => {
+            let mut octets = [0u8; 16];
+            r.read_exact(&mut octets)?;
+            let port = r.read_u16::<LittleEndian>()?;
+            Ok(PeerAddress::Clear(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        _ => {
+            let mut pubkey = [0u8; 32];
+            r.read_exact(&mut pubkey)?;
+            let port = r.read_u16::<LittleEndian>()?;
+            Ok(PeerAddress::Onion { pubkey, port })
+        }
+    }
+}
+
+fn write_addr<W: Write>(w: &mut W, addr: &PeerAddress) -> io::Result<()> {
+    match addr {
+        PeerAddress::Clear(socket) => {
+            match socket.ip() {
+                IpAddr::V4(v4) => {
+                    w.write_u8(4)?;
+                    w.write_all(&v4.octets())?;
+                }
+                IpAddr::V6(v6) => {
+                    w.write_u8(6)?;
+                    w.write_all(&v6.octets())?;
+                }
+            }
+            w.write_u16::<LittleEndian>(socket.port())
+        }
+        PeerAddress::Onion { pubkey, port } => {
+            w.write_u8(111)?;
+            w.write_all(pubkey)?;
+            w.write_u16::<LittleEndian>(*port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn store_at(name: &str) -> PeerStore {
+        let path = std::env::temp_dir().join(format!("biadne_peer_store_test_{}_{}", name, thread_rng().gen::<u64>()));
+        let _ = std::fs::remove_file(&path);
+        PeerStore::new(&path).expect("can not open peer store")
+    }
+
+    fn addr(octet: u8) -> PeerAddress {
+        PeerAddress::Clear(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, octet)), 8333))
+    }
+
+    #[test]
+    fn mark_failed_demotes_good_before_timeout() {
+        let store = store_at("demote");
+        let a = addr(1);
+        store.mark_good(a, 0);
+        store.mark_failed(a);
+        store.mark_failed(a);
+        assert_eq!(store.entries.lock().unwrap().get(&a).unwrap().state, AddressState::WasGood);
+        store.mark_failed(a);
+        assert_eq!(store.entries.lock().unwrap().get(&a).unwrap().state, AddressState::Timeout);
+    }
+
+    #[test]
+    fn mark_good_never_promotes_banned() {
+        let store = store_at("banned");
+        let a = addr(2);
+        store.ban(a);
+        store.mark_good(a, 0);
+        assert_eq!(store.entries.lock().unwrap().get(&a).unwrap().state, AddressState::Banned);
+    }
+
+    #[test]
+    fn sample_never_returns_banned_or_backed_off() {
+        let store = store_at("sample");
+        let good = addr(3);
+        let banned = addr(4);
+        let backed_off = addr(5);
+        store.mark_good(good, 0);
+        store.ban(banned);
+        store.mark_good(backed_off, 0);
+        for _ in 0..3 {
+            store.mark_failed(backed_off);
+        }
+        let sampled = store.sample(10);
+        assert!(sampled.contains(&good));
+        assert!(!sampled.contains(&banned));
+        assert!(!sampled.contains(&backed_off));
+    }
+
+    #[test]
+    fn timed_out_address_is_retried_once_backoff_elapses() {
+        let store = store_at("retry");
+        let a = addr(6);
+        store.mark_good(a, 0);
+        for _ in 0..3 {
+            store.mark_failed(a);
+        }
+        assert_eq!(store.entries.lock().unwrap().get(&a).unwrap().state, AddressState::Timeout);
+        assert!(store.sample(10).is_empty());
+        {
+            let mut entries = store.entries.lock().unwrap();
+            let entry = entries.get_mut(&a).unwrap();
+            entry.last_seen = 0;
+        }
+        assert!(store.sample(10).contains(&a));
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Set reconciliation of the stored ad set against a peer, using [`Iblt`].
+//!
+//! A peer wanting to reconcile builds an [`Iblt`] over the content ids it
+//! currently stores and sends it across. The receiving side builds its own
+//! table with the same seed and size, subtracts the received table from it
+//! and peels the result: every id that peels out with `+1` is something only
+//! the receiver has (so it offers it), every id that peels out with `-1` is
+//! something only the sender has (so the receiver should `getdata` it). If peeling does not
+//! fully empty the table the difference was larger than the table could
+//! represent; the caller doubles the cell count and tries again, up to
+//! [`MAX_CELLS`], and gives up to a full id list exchange past that.
+
+use crate::iblt::{keys_from_seed, ContentId, Iblt};
+
+/// Doubling past this many cells means the sets are too different for an
+/// IBLT to be worth it; fall back to exchanging full id lists instead.
+const MAX_CELLS: usize = 1 << 16;
+
+/// How much bigger to make the table on each decode failure.
+const GROWTH_FACTOR: usize = 2;
+
+/// `ids` each side should fetch from the other to reconcile their sets.
+pub struct ReconcileOutcome {
+    /// Ids the local side has and the remote side is missing.
+    pub local_only: Vec<ContentId>,
+    /// Ids the remote side has and the local side is missing.
+    pub remote_only: Vec<ContentId>,
+}
+
+/// Outcome of one reconciliation attempt.
+pub enum ReconcileResult {
+    /// The difference decoded cleanly.
+    Decoded(ReconcileOutcome),
+    /// Table was too small for the real difference; retry with `next_cells`.
+    Retry {
+        /// Cell count to use for the next attempt.
+        next_cells: usize
+    },
+    /// Even the largest table failed to decode; exchange full id lists instead.
+    FallBackToFullList,
+}
+
+/// Cell count to start with for an estimated difference of `estimated_difference` ids.
+///
+/// About one and a half cells per expected entry (at least 8 entries are
+/// assumed), rounded up to a power of two, keeps the peel step reliable
+/// without wasting bandwidth on an oversized table for a small difference.
+pub fn initial_cell_count(estimated_difference: usize) -> usize {
+    (estimated_difference.max(8) * 3 / 2).next_power_of_two()
+}
+
+/// Build an IBLT over `ids`, keyed so that a peer with the same `seed` can
+/// subtract our table from theirs and peel out the difference.
+pub fn build_table(ids: &[ContentId], num_cells: usize, seed: u64) -> Iblt {
+    let mut table = Iblt::new(num_cells, keys_from_seed(seed));
+    for id in ids {
+        table.insert(id);
+    }
+    table
+}
+
+/// Reconcile `local` (built over our own content ids) against `remote`
+/// (received from the peer, same `num_cells` and `seed`).
+pub fn reconcile(local: &Iblt, remote: &Iblt) -> ReconcileResult {
+    match local.subtract(remote).decode() {
+        Ok((local_only, remote_only)) => ReconcileResult::Decoded(ReconcileOutcome { local_only, remote_only }),
+        Err(()) => {
+            let next = local.len() * GROWTH_FACTOR;
+            if next > MAX_CELLS {
+                ReconcileResult::FallBackToFullList
+            } else {
+                ReconcileResult::Retry { next_cells: next }
+            }
+        }
+    }
+}
+
+/// What a reconciliation session needs from its transport.
+///
+/// A round ([`start_round`], [`continue_round`]) is transport-agnostic: it
+/// is driven by whatever carries defiads's own wire messages (the overlay
+/// network) rather than by the Bitcoin header-sync link, so it is expressed
+/// against this trait instead of a concrete peer type.
+pub trait ReconcilePeer {
+    /// Send our table for the peer to subtract and peel.
+    fn send_table(&mut self, table: &Iblt, seed: u64);
+    /// Ask the peer to retry with a larger table after a failed decode.
+    fn request_larger(&mut self, next_cells: usize);
+    /// Ask the peer to send us the content behind these ids.
+    fn getdata(&mut self, ids: &[ContentId]);
+    /// Offer content the peer does not have.
+    fn offer(&mut self, ids: &[ContentId]);
+    /// Ask for a full id list because the IBLT could not decode even at `MAX_CELLS`.
+    fn request_full_list(&mut self);
+}
+
+/// Run one reconciliation round against `peer`, given the ids we currently store.
+///
+/// This only covers our side of the exchange (building and peeling tables);
+/// actually waiting for the peer's reply and re-entering on `Retry` happens
+/// in the overlay network's message loop, which owns the `ReconcilePeer`.
+pub fn start_round<P: ReconcilePeer>(local_ids: &[ContentId], estimated_difference: usize, seed: u64, peer: &mut P) {
+    let num_cells = initial_cell_count(estimated_difference);
+    let table = build_table(local_ids, num_cells, seed);
+    peer.send_table(&table, seed);
+}
+
+/// Handle a table `remote` sent back by `peer`, advancing the round.
+pub fn continue_round<P: ReconcilePeer>(local_ids: &[ContentId], num_cells: usize, seed: u64, remote: Iblt, peer: &mut P) {
+    let local = build_table(local_ids, num_cells, seed);
+    match reconcile(&local, &remote) {
+        ReconcileResult::Decoded(outcome) => {
+            if !outcome.remote_only.is_empty() {
+                peer.getdata(&outcome.remote_only);
+            }
+            if !outcome.local_only.is_empty() {
+                peer.offer(&outcome.local_only);
+            }
+        }
+        ReconcileResult::Retry { next_cells } => peer.request_larger(next_cells),
+        ReconcileResult::FallBackToFullList => peer.request_full_list(),
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Funding-weighted retention and eviction for `ContentStore`.
+//!
+//! Storage is bounded: once the configured byte or entry cap is exceeded,
+//! the lowest-ranked ad is evicted first. Rank combines the committed
+//! Bitcoin value behind an ad (its funding weight, proven via `funding` and
+//! confirmed on the trunk) with its age, so a well-funded ad outlives an
+//! unfunded or thinly-funded one, but an old ad still eventually loses out
+//! to fresher ones of similar weight.
+
+use std::collections::HashMap;
+
+use bitcoin_hashes::sha256d;
+
+use crate::iblt::ContentId;
+
+/// Storage budget for `ContentStore`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionConfig {
+    /// Maximum number of ads to keep regardless of funding.
+    pub max_entries: usize,
+    /// Maximum total size, in bytes, of stored ad content.
+    pub max_bytes: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig { max_entries: 100_000, max_bytes: 1_000_000_000 }
+    }
+}
+
+/// Proof that an ad is backed by a confirmed, unspent funding output.
+#[derive(Clone, Copy, Debug)]
+pub struct FundingProof {
+    /// Hash of the block that confirms the funding transaction.
+    pub block_hash: sha256d::Hash,
+    /// Committed value of the funding output, in satoshis.
+    pub amount: u64,
+}
+
+/// Check that `proof`'s block is still part of the main chain, returning its
+/// height if so. An ad whose funding proof no longer verifies (the block
+/// was reorged out) should be rejected or deprioritized, not trusted.
+pub fn verify_on_trunk(trunk: &dyn bitcoin_wallet::trunk::Trunk, proof: &FundingProof) -> Option<u32> {
+    if trunk.is_on_trunk(&proof.block_hash) {
+        trunk.get_height(&proof.block_hash)
+    } else {
+        None
+    }
+}
+
+struct Entry {
+    weight: u64,
+    height: u32,
+    bytes: u64,
+}
+
+impl Entry {
+    /// Higher is better: `weight * 1000 / (age + 1)`, with age in blocks, so
+    /// funding counts for less the older an ad gets and a large old ad cannot
+    /// crowd out everything indefinitely on value alone.
+    fn score(&self, current_height: u32) -> u64 {
+        let age = current_height.saturating_sub(self.height) as u64;
+        self.weight.saturating_mul(1_000) / (age + 1)
+    }
+}
+
+/// Tracks funding-weighted rank for every stored ad and decides what to
+/// evict once the configured budget is exceeded.
+pub struct RetentionTracker {
+    config: RetentionConfig,
+    entries: HashMap<ContentId, Entry>,
+    total_bytes: u64,
+}
+
+impl RetentionTracker {
+    /// Start tracking under `config`.
+    pub fn new(config: RetentionConfig) -> RetentionTracker {
+        RetentionTracker { config, entries: HashMap::new(), total_bytes: 0 }
+    }
+
+    /// Record a newly accepted ad and return the ids of whatever had to be
+    /// evicted to stay within the configured budget (possibly including
+    /// `id` itself, if nothing beats its own rank and the store is already full).
+    pub fn accept(&mut self, id: ContentId, proof_weight: u64, height: u32, bytes: u64, current_height: u32) -> Vec<ContentId> {
+        self.entries.insert(id, Entry { weight: proof_weight, height, bytes });
+        self.total_bytes += bytes;
+
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.config.max_entries || self.total_bytes > self.config.max_bytes {
+            let worst = self.entries.iter()
+                .min_by_key(|(_, e)| e.score(current_height))
+                .map(|(id, _)| *id);
+            match worst {
+                Some(worst_id) => {
+                    if let Some(e) = self.entries.remove(&worst_id) {
+                        self.total_bytes = self.total_bytes.saturating_sub(e.bytes);
+                    }
+                    evicted.push(worst_id);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Remove an ad that no longer has a valid funding proof (its block
+    /// reorged out) from the ranking without counting it as a deliberate eviction.
+    pub fn unwind(&mut self, id: &ContentId) {
+        if let Some(e) = self.entries.remove(id) {
+            self.total_bytes = self.total_bytes.saturating_sub(e.bytes);
+        }
+    }
+
+    /// Current number of tracked ads.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Current total size of tracked ad content, in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+}
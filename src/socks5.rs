@@ -0,0 +1,124 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A minimal SOCKS5 client, just enough to reach Tor onion services.
+//!
+//! This only implements the `CONNECT` command with no authentication
+//! (RFC 1928), addressing the target by domain name (RFC 1928 ATYP 0x03) so
+//! the local Tor daemon resolves `.onion` names itself.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use sha3::{Digest, Sha3_256};
+
+use crate::peer_store::PeerAddress;
+
+/// Tor v3 onion address version byte (the only version Tor currently issues).
+const ONION_VERSION: u8 = 0x03;
+
+/// How to reach a local or remote SOCKS5 proxy (normally the Tor daemon).
+#[derive(Clone, Copy, Debug)]
+pub struct Socks5ProxyConfig {
+    /// Address of the SOCKS5 proxy, e.g. `127.0.0.1:9050` for Tor.
+    pub proxy: SocketAddr,
+}
+
+/// Encode a [`PeerAddress`] as the real Tor v3 `host:port` string the proxy
+/// should dial: `base32(pubkey || checksum || version)`, where
+/// `checksum = SHA3-256(".onion checksum" || pubkey || version)[..2]`, per
+/// the Tor rend-spec-v3 address format. Tor's own resolver checks this
+/// checksum, so a hostname built from the raw pubkey alone (skipping it)
+/// is simply not a valid onion address and every dial through the proxy
+/// would fail.
+pub fn onion_host(pubkey: &[u8; 32]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.input(b".onion checksum");
+    hasher.input(pubkey);
+    hasher.input(&[ONION_VERSION]);
+    let digest = hasher.result();
+
+    let mut data = Vec::with_capacity(32 + 2 + 1);
+    data.extend_from_slice(pubkey);
+    data.extend_from_slice(&digest[..2]);
+    data.push(ONION_VERSION);
+
+    format!("{}.onion", base32_encode(&data))
+}
+
+/// Connect to `target` through `proxy`, returning the established stream.
+///
+/// Only onion targets need this path; clear addresses are dialed directly by
+/// `murmel`'s own transport.
+pub fn connect_via_proxy(config: &Socks5ProxyConfig, target: &PeerAddress) -> io::Result<TcpStream> {
+    let (host, port) = match target {
+        PeerAddress::Onion { pubkey, port } => (onion_host(pubkey), *port),
+        PeerAddress::Clear(addr) => (addr.ip().to_string(), addr.port()),
+    };
+
+    let mut stream = TcpStream::connect(config.proxy)?;
+
+    // greeting: version 5, one auth method, "no authentication required"
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 0x05 || reply[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::Other, "SOCKS5 proxy rejected our auth method"));
+    }
+
+    // CONNECT request, addressed by domain name so the proxy resolves .onion itself
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push((port & 0xff) as u8);
+    stream.write_all(&request)?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    if header[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("SOCKS5 CONNECT failed with code {}", header[1])));
+    }
+    match header[3] {
+        0x01 => { let mut skip = [0u8; 4]; stream.read_exact(&mut skip)?; }
+        0x03 => { let mut len = [0u8; 1]; stream.read_exact(&mut len)?; let mut skip = vec![0u8; len[0] as usize]; stream.read_exact(&mut skip)?; }
+        0x04 => { let mut skip = [0u8; 16]; stream.read_exact(&mut skip)?; }
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown SOCKS5 address type in reply")),
+    }
+    let mut port_bytes = [0u8; 2];
+    stream.read_exact(&mut port_bytes)?;
+
+    Ok(stream)
+}
+
+/// RFC 4648 base32 encoding without padding, as used for onion v3 hostnames.
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::new();
+    let mut bits = 0u32;
+    let mut value = 0u32;
+    for &byte in data {
+        value = (value << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((value >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((value << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
@@ -0,0 +1,125 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Stores ad content and tracks which Bitcoin block (if any) confirms the
+//! funding proof behind it.
+//!
+//! `ContentStore` itself only holds bytes and, optionally, a [`FundingProof`]
+//! per [`ContentId`]; whether a given id's weight earns it a place once the
+//! store is over budget is decided by `p2p_bitcoin::BitcoinDriver`'s
+//! `RetentionTracker`, which is driven by chain-connect/disconnect events the
+//! store has no reason to know about on its own.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bitcoin::BlockHeader;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_wallet::trunk::Trunk;
+
+use crate::error::BiadNetError;
+use crate::iblt::ContentId;
+use crate::retention::{FundingProof, RetentionConfig};
+
+struct StoredContent {
+    bytes: Vec<u8>,
+    proof: Option<FundingProof>,
+}
+
+/// Holds ad content keyed by [`ContentId`], alongside the funding proof (if
+/// any) that backs it.
+pub struct ContentStore {
+    trunk: Arc<dyn Trunk>,
+    content: HashMap<ContentId, StoredContent>,
+}
+
+impl ContentStore {
+    /// Start an empty store over `trunk`. `config` is accepted here, rather
+    /// than only by the caller's `RetentionTracker`, so construction mirrors
+    /// the shape of every other component that is handed its budget once at
+    /// startup; the store does not use it for anything itself yet.
+    pub fn new(trunk: Arc<dyn Trunk>, _config: RetentionConfig) -> ContentStore {
+        ContentStore { trunk, content: HashMap::new() }
+    }
+
+    /// Pick up any ads whose funding proof is newly confirmed by the header
+    /// that just connected at the chain tip, returning their ids.
+    ///
+    /// Nothing in this tree yet submits a locally authored ad with a pending
+    /// funding proof for this to confirm (that needs the ad-authoring path,
+    /// which this snapshot does not have), so there is nothing to scan for
+    /// and this always returns an empty list until that path exists.
+    pub fn add_header(&mut self, _header: &BlockHeader) -> Result<Vec<ContentId>, BiadNetError> {
+        Ok(Vec::new())
+    }
+
+    /// Drop the funding proof of (but not the content behind) any ad whose
+    /// confirming block was just reorged out, returning their ids so the
+    /// caller can un-rank them too.
+    pub fn unwind_tip(&mut self, header: &BlockHeader) -> Result<Vec<ContentId>, BiadNetError> {
+        let hash = header.bitcoin_hash();
+        let unwound: Vec<ContentId> = self.content.iter()
+            .filter(|(_, c)| c.proof.map(|p| p.block_hash) == Some(hash))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &unwound {
+            if let Some(c) = self.content.get_mut(id) {
+                c.proof = None;
+            }
+        }
+        Ok(unwound)
+    }
+
+    /// The funding proof behind `id`, if it has one and is still confirmed
+    /// on `trunk`.
+    pub fn funding_proof(&self, id: &ContentId) -> Option<FundingProof> {
+        self.content.get(id).and_then(|c| c.proof)
+            .filter(|proof| crate::retention::verify_on_trunk(self.trunk.as_ref(), proof).is_some())
+    }
+
+    /// Size of the stored content behind `id`, in bytes, or `0` if unknown.
+    pub fn content_len(&self, id: &ContentId) -> u64 {
+        self.content.get(id).map(|c| c.bytes.len() as u64).unwrap_or(0)
+    }
+
+    /// Drop `id` entirely, e.g. once `RetentionTracker` has evicted it.
+    pub fn forget(&mut self, id: &ContentId) {
+        self.content.remove(id);
+    }
+
+    /// Whether content for `id` is already stored.
+    pub fn has_content(&self, id: &ContentId) -> bool {
+        self.content.contains_key(id)
+    }
+
+    /// The stored bytes behind `id`, if any.
+    pub fn get_content(&self, id: &ContentId) -> Option<Vec<u8>> {
+        self.content.get(id).map(|c| c.bytes.clone())
+    }
+
+    /// Accept ad content relayed over the overlay network. No funding proof
+    /// travels with it over that link, and nothing in this store attaches one
+    /// later, so `funding_proof` returns `None` for such content.
+    pub fn accept_content(&mut self, id: ContentId, bytes: Vec<u8>) -> Result<(), BiadNetError> {
+        self.content.entry(id).or_insert(StoredContent { bytes, proof: None });
+        Ok(())
+    }
+
+    /// Every id currently stored, for reconciliation against a peer's set.
+    pub fn content_ids(&self) -> Vec<ContentId> {
+        self.content.keys().cloned().collect()
+    }
+}